@@ -0,0 +1,202 @@
+//! Asynchronous counterpart to [`PleaseHandle`](crate::PleaseHandle), built
+//! on top of `diesel-async`.
+//!
+//! This module mirrors the synchronous API almost exactly; the only
+//! differences are that every method returns a `Future` instead of blocking,
+//! and that the closure passed to [`AsyncPleaseHandle::transaction`] is
+//! itself `async`.
+//!
+//! This module is only available when the `async` feature is enabled, since
+//! it pulls in `diesel-async` as an additional dependency.
+//!
+//! Unlike [`PleaseHandle`](crate::PleaseHandle), this module has no
+//! automated test coverage: exercising it needs an async test harness (e.g.
+//! a `#[tokio::test]` dev-dependency), which isn't wired up in this crate.
+
+use async_trait::async_trait;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::dsl;
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use diesel_async::scoped_futures::{self, ScopedFutureExt};
+
+use crate::schema::*;
+use crate::{ExpiredId, PleaseError, PleaseId, PleaseResult};
+
+/// Trait for types providing asynchronous database connections. This is the
+/// `async` counterpart to [`ConnectionProvider`](crate::ConnectionProvider).
+#[async_trait]
+pub trait AsyncConnectionProvider {
+    /// Error type which may be returned when the provider is unable
+    /// to obtain a database connection.
+    type Error;
+    /// Type of the connection returned. Currently the connection must
+    /// use the postgres backend.
+    type Connection: AsyncConnection<Backend = Pg> + Send;
+    /// Obtain a connection from this provider.
+    async fn get(&self) -> Result<Self::Connection, Self::Error>;
+}
+
+/// This handle identifies a long-running operation using a unique integer.
+///
+/// See [`PleaseHandle`](crate::PleaseHandle) for the full description of how
+/// handles are used; this type exposes the same operations, but driven
+/// through `diesel-async` so that none of them block the current thread.
+///
+/// Unlike `PleaseHandle`, this type does *not* close itself on drop: running
+/// an async destructor requires a runtime, which `Drop` cannot assume.
+/// Dropping an `AsyncPleaseHandle` without calling [`close`](Self::close)
+/// therefore leaks its `please_ids` row until the next `perform_cleanup`
+/// notices it has expired.
+#[derive(Debug)]
+pub struct AsyncPleaseHandle<P: AsyncConnectionProvider> {
+    provider: P,
+    id: i32,
+}
+
+impl<P: AsyncConnectionProvider> AsyncPleaseHandle<P> {
+    async fn transaction_internal<R, E, F>(provider: &P, f: F) -> Result<R, E>
+    where
+        R: Send,
+        E: From<PleaseError<P::Error>> + Send,
+        F: for<'c> FnOnce(&'c mut P::Connection) -> scoped_futures::ScopedBoxFuture<'c, 'c, Result<R, E>>
+            + Send,
+    {
+        let mut conn = provider.get().await.map_err(PleaseError::Provider)?;
+
+        conn.transaction(|conn| f(conn)).await
+    }
+
+    /// Construct a new handle using the specified connection provider.
+    ///
+    /// See [`PleaseHandle::new`](crate::PleaseHandle::new) for details; this
+    /// is identical other than being asynchronous.
+    pub async fn new(provider: P, title: &str) -> PleaseResult<Self, P::Error> {
+        let title = title.to_owned();
+
+        let id: i32 = Self::transaction_internal(&provider, move |conn| {
+            async move {
+                Ok(diesel::insert_into(please_ids::table)
+                    .values(&please_ids::title.eq(title))
+                    .returning(please_ids::id)
+                    .get_result(conn)
+                    .await?)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+        Ok(AsyncPleaseHandle { provider, id })
+    }
+
+    /// Convenience constructor.
+    ///
+    /// Equivalent to calling [`perform_cleanup`](Self::perform_cleanup)
+    /// followed by [`new`](Self::new).
+    pub async fn new_with_cleanup(provider: P, title: &str) -> PleaseResult<Self, P::Error> {
+        let _ = Self::perform_cleanup(&provider).await;
+        Self::new(provider, title).await
+    }
+
+    /// Explicitly clean up old handles. It is recommended to call this
+    /// before creating a new handle.
+    ///
+    /// This function returns the expired handles (if any) so that you can
+    /// log them or use them for debugging.
+    pub async fn perform_cleanup(provider: &P) -> PleaseResult<Vec<ExpiredId>, P::Error> {
+        Self::transaction_internal(provider, |conn| {
+            async move {
+                diesel::delete(please_ids::table.filter(please_ids::expiry.lt(dsl::now)))
+                    .get_results::<PleaseId>(conn)
+                    .await
+                    .map_err(PleaseError::Query)
+                    .map(|v| v.into_iter().map(ExpiredId).collect())
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Run a transaction as part of the operation this handle represents.
+    ///
+    /// Behaves exactly like
+    /// [`PleaseHandle::transaction`](crate::PleaseHandle::transaction),
+    /// except that the callback is itself asynchronous.
+    pub async fn transaction<R, E, F, Fut>(&mut self, f: F) -> Result<R, E>
+    where
+        R: Send,
+        E: From<PleaseError<P::Error>> + Send,
+        F: for<'c> FnOnce(&'c mut P::Connection, i32) -> Fut + Send,
+        Fut: std::future::Future<Output = Result<R, E>> + Send,
+    {
+        let id = self.id;
+
+        Self::transaction_internal(&self.provider, move |conn| {
+            async move {
+                let num_rows = diesel::update(please_ids::table.filter(please_ids::id.eq(id)))
+                    .set(please_ids::refresh_count.eq(please_ids::refresh_count + 1))
+                    .execute(conn)
+                    .await
+                    .map_err(PleaseError::Query)?;
+
+                if num_rows == 1 {
+                    f(conn, id).await
+                } else {
+                    Err(PleaseError::Expired.into())
+                }
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    /// Refreshes the handle, resetting the time before it will be
+    /// automatically closed.
+    ///
+    /// This is equivalent to running an empty transaction.
+    pub async fn refresh(&mut self) -> PleaseResult<(), P::Error> {
+        self.transaction(|_conn, _id| async { Ok(()) }).await
+    }
+
+    /// Expire the handle. Future operations on this handle will fail with
+    /// the error `Expired`.
+    ///
+    /// Useful for testing.
+    pub async fn expire(&mut self) -> PleaseResult<ExpiredId, P::Error> {
+        let id = self.id;
+
+        Self::transaction_internal(&self.provider, move |conn| {
+            async move {
+                diesel::delete(please_ids::table.filter(please_ids::id.eq(id)))
+                    .get_result::<PleaseId>(conn)
+                    .await
+                    .optional()?
+                    .ok_or(PleaseError::Expired)
+            }
+            .scope_boxed()
+        })
+        .await
+        .map(ExpiredId)
+    }
+
+    /// Close the handle, allowing any errors to be handled.
+    ///
+    /// Unlike `PleaseHandle::close`, this is *not* called automatically when
+    /// the handle is dropped (there is no async `Drop`), so forgetting to
+    /// call this leaks the `please_ids` row until `perform_cleanup` next
+    /// notices it has expired. Always call this explicitly when you are
+    /// done with the handle.
+    pub async fn close(mut self) -> PleaseResult<(), P::Error> {
+        self.expire().await?;
+        self.id = -1;
+        Ok(())
+    }
+
+    /// Get the ID of this handle.
+    ///
+    /// A good rule of thumb is to never use this outside of a transaction,
+    /// as in that case it may not have been recently validated.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+}
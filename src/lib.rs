@@ -114,21 +114,43 @@
 //! 
 //! The operation timeout is controlled by a database function: `please_timeout()`.
 //! To change the timeout, use a migration to alter this function and return a
-//! different value. It is not currently possible to change the timeout on a per-operation
-//! basis.
-//! 
+//! different value. If a particular handle needs a different timeout, construct
+//! it with `PleaseHandle::new_with_timeout` instead, which stores the timeout on
+//! the row itself and takes priority over `please_timeout()`.
+//!
+//! Call `PleaseHandle::deadline` to find out when a live handle will expire
+//! if it isn't refreshed again.
+//!
 //! It is recommended to set the operation timeout to as short a time as possible, so
 //! that if your application crashes, is terminated unexpectedly, or simply loses
 //! connectivity to the database, any locks it might have held are released as
 //! soon as possible.
-//! 
+//!
 //! The operation timeout is by default set to *two minutes*.
+//!
+//! # Async
+//!
+//! If you enable the `async` feature, the [`async_handle`] module provides
+//! `AsyncPleaseHandle`, a `diesel-async` based counterpart to `PleaseHandle`
+//! with the same API, but returning `Future`s instead of blocking.
+//!
+//! # Waiting for a lock
+//!
+//! `PleaseHandle::acquire` repeatedly retries a claiming transaction with
+//! exponential backoff until `busy_timeout` elapses. If you enable the
+//! `notify` feature, the [`notify`] module additionally provides
+//! `PleaseHandle::wait_until_free`, which blocks on a `LISTEN`/`NOTIFY`
+//! channel instead of polling.
 #![allow(proc_macro_derive_resolution_fallback)]
 #![deny(missing_docs)]
 
 use std::marker::PhantomData;
 use std::error::Error;
 use std::fmt::{Formatter, Display, self};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 #[macro_use]
 extern crate diesel;
@@ -138,11 +160,18 @@ use chrono::NaiveDateTime;
 
 use diesel::Connection;
 use diesel::pg::Pg;
+use diesel::pg::data_types::PgInterval;
 use diesel::prelude::*;
 use diesel::dsl;
 
 mod schema;
 
+#[cfg(feature = "async")]
+pub mod async_handle;
+
+#[cfg(feature = "notify")]
+pub mod notify;
+
 #[derive(Queryable, Debug, Clone)]
 struct PleaseId {
     id: i32,
@@ -150,12 +179,30 @@ struct PleaseId {
     expiry: NaiveDateTime,
     title: String,
     refresh_count: i32,
+    timeout: Option<PgInterval>,
 }
 
 /// Expired ID, only used for logging/debug purposes
 #[derive(Debug, Clone)]
 pub struct ExpiredId(PleaseId);
 
+impl ExpiredId {
+    /// The effective deadline this handle expired at: `creation`/`refresh`
+    /// time plus whichever of `timeout` or `please_timeout()` applied to it.
+    pub fn deadline(&self) -> NaiveDateTime {
+        self.0.expiry
+    }
+}
+
+/// Summary statistics returned by `PleaseHandle::perform_cleanup_batched`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupStats {
+    /// Total number of expired handles removed, across all batches.
+    pub expired_count: usize,
+    /// Number of batches it took to remove them.
+    pub batches: usize,
+}
+
 
 /// Error type returned by this library
 #[derive(Debug, PartialEq)]
@@ -167,6 +214,9 @@ pub enum PleaseError<P> {
     Query(diesel::result::Error),
     /// Tried to use a handle which had already expired.
     Expired,
+    /// `PleaseHandle::acquire` gave up after `busy_timeout` elapsed without
+    /// the lock becoming free.
+    Busy,
 
     #[doc(hidden)]
     __Nonexhaustive,
@@ -178,6 +228,7 @@ impl<P: Error> Display for PleaseError<P> {
             &PleaseError::Provider(ref e) => Display::fmt(e, f),
             &PleaseError::Query(ref e) => Display::fmt(e, f),
             &PleaseError::Expired => Display::fmt(self.description(), f),
+            &PleaseError::Busy => Display::fmt(self.description(), f),
             &PleaseError::__Nonexhaustive => unreachable!(),
         }
     }
@@ -189,6 +240,7 @@ impl<P: Error> Error for PleaseError<P> {
             &PleaseError::Provider(ref e) => e.description(),
             &PleaseError::Query(ref e) => e.description(),
             &PleaseError::Expired => "The `please` handle has expired and can no longer be used",
+            &PleaseError::Busy => "Gave up waiting for the lock to become free",
             &PleaseError::__Nonexhaustive => unreachable!(),
         }
     }
@@ -197,6 +249,7 @@ impl<P: Error> Error for PleaseError<P> {
             &PleaseError::Provider(ref e) => Some(e),
             &PleaseError::Query(ref e) => Some(e),
             &PleaseError::Expired => None,
+            &PleaseError::Busy => None,
             &PleaseError::__Nonexhaustive => unreachable!(),
         }
     }
@@ -275,8 +328,34 @@ impl<P: ConnectionProvider> PleaseHandle<P> {
         Ok(PleaseHandle { provider, id })
     }
 
+    /// Construct a new handle with a timeout that overrides `please_timeout()`
+    /// for this handle alone.
+    ///
+    /// Use this when a particular operation is known to need longer (or shorter)
+    /// than the global default before it is considered expired.
+    pub fn new_with_timeout(provider: P, title: &str, timeout: Duration) -> PleaseResult<Self, P::Error> {
+        use self::schema::*;
+
+        let timeout = PgInterval::from_microseconds(
+            timeout.as_secs() as i64 * 1_000_000 + i64::from(timeout.subsec_micros())
+        );
+
+        // Allocate a new ID
+        let id: i32 = Self::transaction_internal(&provider, |conn| -> PleaseResult<i32, P::Error> {
+            Ok(diesel::insert_into(please_ids::table)
+                .values((
+                    please_ids::title.eq(title),
+                    please_ids::timeout.eq(timeout),
+                ))
+                .returning(please_ids::id)
+                .get_result(conn)?)
+        })?;
+
+        Ok(PleaseHandle { provider, id })
+    }
+
     /// Convenience constructor.
-    /// 
+    ///
     /// Equivalent to calling `perform_cleanup` followed by `new`.
     /// If you wish to handle expired handles (eg. record them to a log) then
     /// call the methods individually.
@@ -325,8 +404,55 @@ impl<P: ConnectionProvider> PleaseHandle<P> {
         })
     }
 
+    /// Like `perform_cleanup`, but deletes expired rows in bounded batches of
+    /// at most `batch_size`, rather than in a single unbounded `DELETE`.
+    ///
+    /// This is useful once `please_ids` accumulates many stale rows under
+    /// heavy churn, where a single `DELETE ... WHERE expiry < now()` could
+    /// otherwise hold a long-lived lock over an unbounded number of rows.
+    /// Unlike `perform_cleanup`, the expired rows themselves are not
+    /// materialized; only summary statistics are returned.
+    ///
+    /// A `batch_size` of `0` deletes nothing and returns immediately with
+    /// empty statistics, rather than looping forever on empty batches.
+    pub fn perform_cleanup_batched(provider: &P, batch_size: usize) -> PleaseResult<CleanupStats, P::Error> {
+        use self::schema::*;
+
+        let mut stats = CleanupStats { expired_count: 0, batches: 0 };
+
+        if batch_size == 0 {
+            return Ok(stats);
+        }
+
+        loop {
+            let removed = Self::transaction_internal(provider, |conn| {
+                diesel::delete(
+                    please_ids::table.filter(
+                        please_ids::id.eq_any(
+                            please_ids::table
+                                .select(please_ids::id)
+                                .filter(please_ids::expiry.lt(dsl::now))
+                                .limit(batch_size as i64)
+                        )
+                    )
+                )
+                .execute(conn)
+                .map_err(PleaseError::Query)
+            })?;
+
+            stats.batches += 1;
+            stats.expired_count += removed;
+
+            if removed < batch_size {
+                break;
+            }
+        }
+
+        Ok(stats)
+    }
+
     /// Run a transaction as part of the operation this handle represents.
-    /// 
+    ///
     /// After beginning the transaction, this method validates that the handle
     /// has not expired, whilst also refreshing the expiry and taking a lock
     /// on the row, to prevent it being expired by another thread whilst this
@@ -362,14 +488,69 @@ impl<P: ConnectionProvider> PleaseHandle<P> {
         })
     }
 
+    /// Repeatedly attempts to acquire a lock via the claiming transaction `f`.
+    ///
+    /// `f` is run the same way as the callback to `transaction`, except that it
+    /// returns `Ok(None)` to indicate the lock is currently held by someone else,
+    /// rather than treating that as an error. On seeing `Ok(None)`, this method
+    /// sleeps for an exponentially increasing backoff and tries again, until the
+    /// accumulated sleep time reaches `busy_timeout`, at which point it gives up
+    /// and returns `PleaseError::Busy`.
+    pub fn acquire<R, E, F>(&mut self, busy_timeout: Duration, mut f: F) -> Result<R, E>
+    where
+        E: From<PleaseError<P::Error>>,
+        F: FnMut(&P::Connection, i32) -> Result<Option<R>, E>
+    {
+        const INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+        const MAX_BACKOFF: Duration = Duration::from_millis(100);
+
+        let mut slept = Duration::from_secs(0);
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            if let Some(result) = self.transaction(|conn, id| f(conn, id))? {
+                return Ok(result);
+            }
+
+            if slept >= busy_timeout {
+                return Err(PleaseError::Busy.into());
+            }
+
+            let sleep_for = backoff.min(busy_timeout - slept);
+            thread::sleep(sleep_for);
+            slept += sleep_for;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
     /// Refreshes the handle, resetting the time before it will be automatically closed.
-    /// 
+    ///
     /// This is equivalent to running an empty transaction.
     pub fn refresh(&mut self) -> PleaseResult<(), P::Error> {
         // Just do an empty transaction
         self.transaction(|_conn, _id| Ok(()))
     }
 
+    /// Looks up the effective deadline for this handle: the point in time,
+    /// computed from its creation/last-refresh and either its per-row
+    /// `timeout` or the global `please_timeout()`, at which it will expire
+    /// if not refreshed again.
+    ///
+    /// This requires a database round-trip, since the deadline itself isn't
+    /// cached on the handle.
+    pub fn deadline(&self) -> PleaseResult<NaiveDateTime, P::Error> {
+        use self::schema::*;
+
+        Self::transaction_internal(&self.provider, |conn| {
+            please_ids::table
+                .filter(please_ids::id.eq(self.id))
+                .select(please_ids::expiry)
+                .get_result(conn)
+                .optional()?
+                .ok_or(PleaseError::Expired)
+        })
+    }
+
     /// Expire the handle. Future operations on this handle will fail with the error `Expired`.
     /// 
     /// Useful for testing.
@@ -403,6 +584,102 @@ impl<P: ConnectionProvider> PleaseHandle<P> {
     pub fn id(&self) -> i32 { self.id }
 }
 
+impl<P> PleaseHandle<P>
+where
+    P: ConnectionProvider + Send + 'static,
+    P::Error: Send + 'static,
+{
+    /// Moves this handle onto a background thread which calls `refresh()`
+    /// every `interval`, so that a long-running operation never expires just
+    /// because the caller forgot to refresh it manually.
+    ///
+    /// `interval` should be comfortably shorter than the operation timeout;
+    /// half the timeout is a reasonable starting point. The returned
+    /// `KeepaliveHandle` lets you recover the `PleaseHandle` (and any error
+    /// encountered whilst refreshing in the background) once the work is
+    /// done, via `KeepaliveHandle::join`.
+    pub fn spawn_keepalive(mut self, interval: Duration) -> KeepaliveHandle<P> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let error = Arc::new(Mutex::new(None));
+
+        let thread_stop = stop.clone();
+        let thread_error = error.clone();
+
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+
+                if thread_stop.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                if let Err(e) = self.refresh() {
+                    *thread_error.lock().unwrap() = Some(e);
+                    break;
+                }
+            }
+
+            self
+        });
+
+        KeepaliveHandle {
+            stop,
+            error,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Guard returned by `PleaseHandle::spawn_keepalive`.
+///
+/// Dropping this guard (without calling `join`) stops and joins the
+/// background thread, then discards the `PleaseHandle` it hands back, which
+/// is dropped (and thus expired) on the thread dropping the guard.
+pub struct KeepaliveHandle<P: ConnectionProvider> {
+    stop: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<PleaseError<P::Error>>>>,
+    thread: Option<JoinHandle<PleaseHandle<P>>>,
+}
+
+impl<P: ConnectionProvider> KeepaliveHandle<P> {
+    /// Returns `true` if the background refresh has already failed.
+    ///
+    /// Check this periodically from the work being protected by the handle,
+    /// so that it can abort early rather than running to completion only to
+    /// find the handle expired in the meantime.
+    pub fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    /// Stops the keepalive thread and hands back the `PleaseHandle`, along
+    /// with any error encountered whilst refreshing it in the background.
+    pub fn join(mut self) -> (PleaseHandle<P>, Option<PleaseError<P::Error>>) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        let handle = self.thread.take()
+            .expect("thread already joined")
+            .join()
+            .expect("keepalive thread panicked");
+
+        let error = self.error.lock().unwrap().take();
+
+        (handle, error)
+    }
+}
+
+impl<P: ConnectionProvider> Drop for KeepaliveHandle<P> {
+    /// Stops and joins the background thread, then drops (and thus closes)
+    /// the `PleaseHandle` it returns, so that a guard abandoned without calling `join`
+    /// doesn't leak the keepalive thread or leave the row open forever.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 impl<P: ConnectionProvider> Drop for PleaseHandle<P> {
     /// Closes the handle, ignoring any errors that might
     /// have occurred.
@@ -420,6 +697,7 @@ mod tests {
 
     use super::*;
     use std::env;
+    use std::mem;
 
     #[derive(Copy, Clone, Debug)]
     struct TestConnectionProvider;
@@ -500,4 +778,97 @@ mod tests {
         
         assert_eq!(err, PleaseError::Expired);
     }
+
+    #[test]
+    fn acquire_times_out_when_always_busy() {
+        let mut handle = new_handle("acquire_times_out_when_always_busy");
+
+        let err = handle.acquire(Duration::from_millis(100), |_conn, _id| {
+            Ok::<Option<()>, PleaseError<_>>(None)
+        }).expect_err("Acquire should give up once busy_timeout elapses");
+
+        assert_eq!(err, PleaseError::Busy);
+
+        handle.close()
+            .expect("Failed to close handle");
+    }
+
+    #[test]
+    fn spawn_keepalive_extends_past_raw_timeout() {
+        let handle = PleaseHandle::new_with_timeout(
+            TestConnectionProvider,
+            "spawn_keepalive_extends_past_raw_timeout",
+            Duration::from_millis(50),
+        ).expect("Failed to create handle");
+
+        let keepalive = handle.spawn_keepalive(Duration::from_millis(20));
+
+        // Sleep well past the raw timeout; without the background thread
+        // refreshing it, the handle would already have expired by now.
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(!keepalive.failed());
+
+        let (mut handle, error) = keepalive.join();
+        assert!(error.is_none());
+
+        handle.transaction(|_conn, _id| Ok::<(), PleaseError<_>>(()))
+            .expect("Handle should still be alive thanks to the keepalive thread");
+
+        handle.close()
+            .expect("Failed to close handle");
+    }
+
+    #[test]
+    fn keepalive_handle_closes_row_on_drop() {
+        use self::schema::*;
+
+        let handle = PleaseHandle::new_with_timeout(
+            TestConnectionProvider,
+            "keepalive_handle_closes_row_on_drop",
+            Duration::from_secs(60),
+        ).expect("Failed to create handle");
+
+        let id = handle.id();
+        let keepalive = handle.spawn_keepalive(Duration::from_millis(20));
+
+        // Dropping the guard without calling `join` should still stop the
+        // thread and close the underlying handle.
+        drop(keepalive);
+
+        let conn = TestConnectionProvider.get().expect("Failed to get connection");
+        let remaining: i64 = please_ids::table
+            .filter(please_ids::id.eq(id))
+            .count()
+            .get_result(&conn)
+            .expect("Failed to count rows");
+
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn cleanup_batched_runs_to_completion() {
+        let batch_size = 2;
+        let count = 5;
+
+        for i in 0..count {
+            let handle = PleaseHandle::new_with_timeout(
+                TestConnectionProvider,
+                &format!("cleanup_batched_runs_to_completion::{}", i),
+                Duration::from_millis(1),
+            ).expect("Failed to create handle");
+
+            // Let the row actually expire and be picked up by the batched
+            // cleanup below, rather than deleting it immediately via `Drop`.
+            mem::forget(handle);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+
+        let stats = PleaseHandle::perform_cleanup_batched(&TestConnectionProvider, batch_size)
+            .expect("Failed to clean up expired handles");
+
+        assert!(stats.batches > 1, "expected more than one batch, got {}", stats.batches);
+        assert!(stats.expired_count >= count);
+    }
 }
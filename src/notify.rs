@@ -0,0 +1,213 @@
+//! Event-driven lock waiting built on PostgreSQL's `LISTEN`/`NOTIFY`, so that
+//! callers of [`PleaseHandle::acquire`](crate::PleaseHandle::acquire) don't
+//! have to poll.
+//!
+//! A migration shipped alongside this module installs an `AFTER DELETE`
+//! trigger on `please_ids` which runs `pg_notify('please_expiry', id)`
+//! whenever a handle is expired or closed (including via `perform_cleanup`).
+//! [`PleaseHandle::wait_until_free`] opens a dedicated connection, issues
+//! `LISTEN please_expiry`, and blocks until a relevant notification arrives.
+//!
+//! This module is only available when the `notify` feature is enabled,
+//! since it pulls in the `postgres` crate to drive the dedicated listening
+//! connection (`diesel::PgConnection` has no notification support).
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use postgres::{Client, NoTls};
+use postgres::fallible_iterator::FallibleIterator;
+
+use crate::{ConnectionProvider, PleaseError, PleaseHandle, PleaseResult};
+
+const EXPIRY_CHANNEL: &str = "please_expiry";
+
+/// Extension of [`ConnectionProvider`] for providers that can also hand out
+/// a `libpq` connection string, needed to open the dedicated connection
+/// used for `LISTEN`/`NOTIFY`.
+pub trait NotifyConnectionProvider: ConnectionProvider {
+    /// Returns a `libpq` connection string suitable for establishing a
+    /// dedicated listening connection.
+    fn connection_url(&self) -> String;
+}
+
+/// Error returned by [`PleaseHandle::wait_until_free`].
+#[derive(Debug)]
+pub enum NotifyError<P> {
+    /// An error from the underlying `please` operations used to check
+    /// whether the handle is still blocked.
+    Please(PleaseError<P>),
+    /// An error on the dedicated `LISTEN`/`NOTIFY` connection.
+    Connection(postgres::Error),
+}
+
+impl<P> From<PleaseError<P>> for NotifyError<P> {
+    fn from(other: PleaseError<P>) -> Self {
+        NotifyError::Please(other)
+    }
+}
+
+impl<P> From<postgres::Error> for NotifyError<P> {
+    fn from(other: postgres::Error) -> Self {
+        NotifyError::Connection(other)
+    }
+}
+
+impl<P: Error> Display for NotifyError<P> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &NotifyError::Please(ref e) => Display::fmt(e, f),
+            &NotifyError::Connection(ref e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl<P: Error> Error for NotifyError<P> {
+    fn description(&self) -> &str {
+        match self {
+            &NotifyError::Please(ref e) => e.description(),
+            &NotifyError::Connection(ref e) => e.description(),
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        match self {
+            &NotifyError::Please(ref e) => Some(e),
+            &NotifyError::Connection(ref e) => Some(e),
+        }
+    }
+}
+
+impl<P: NotifyConnectionProvider> PleaseHandle<P> {
+    /// Blocks until whatever this caller is waiting on becomes free, using
+    /// `LISTEN`/`NOTIFY` instead of polling.
+    ///
+    /// `channel_predicate` is called with the id carried by each
+    /// notification on the `please_expiry` channel, and should return `true`
+    /// if that id is one this caller cares about (avoiding a database
+    /// round-trip for unrelated expiries). `still_blocked` performs the
+    /// actual check of whether we can stop waiting, and is also used as a
+    /// fallback: it is checked once before `LISTEN` is issued, and again
+    /// every `poll_interval` if no relevant notification has arrived, to
+    /// guard against a notification being missed (for example, because the
+    /// row was deleted in the gap between the caller's last check and
+    /// `LISTEN` taking effect).
+    pub fn wait_until_free<F, G>(
+        provider: &P,
+        poll_interval: Duration,
+        mut still_blocked: G,
+        mut channel_predicate: F,
+    ) -> Result<(), NotifyError<P::Error>>
+    where
+        F: FnMut(i32) -> bool,
+        G: FnMut() -> PleaseResult<bool, P::Error>,
+    {
+        if !still_blocked()? {
+            return Ok(());
+        }
+
+        let mut client = Client::connect(&provider.connection_url(), NoTls)?;
+        client.batch_execute(&format!("LISTEN {}", EXPIRY_CHANNEL))?;
+
+        // We may have become unblocked whilst establishing the listening
+        // connection, so check again now that we're definitely listening.
+        if !still_blocked()? {
+            return Ok(());
+        }
+
+        let mut notifications = client.notifications();
+        let mut next_poll = Instant::now() + poll_interval;
+
+        loop {
+            let wait = next_poll.saturating_duration_since(Instant::now());
+            match notifications.timeout_iter(wait).next()? {
+                Some(notification) => {
+                    let id: i32 = notification.payload().parse().unwrap_or(-1);
+                    // Unrelated notifications must not postpone the
+                    // periodic fallback check.
+                    if !channel_predicate(id) && Instant::now() < next_poll {
+                        continue;
+                    }
+                }
+                None => {
+                    // Nothing arrived within `poll_interval`; fall back to
+                    // polling in case a notification was missed.
+                }
+            }
+
+            if !still_blocked()? {
+                return Ok(());
+            }
+            next_poll = Instant::now() + poll_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate dotenv;
+
+    use super::*;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use diesel::Connection;
+    use std::env;
+    use std::thread;
+
+    #[derive(Copy, Clone, Debug)]
+    struct TestConnectionProvider;
+
+    impl ConnectionProvider for TestConnectionProvider {
+        type Connection = PgConnection;
+        type Error = diesel::ConnectionError;
+
+        fn get(&self) -> Result<PgConnection, Self::Error> {
+            dotenv::dotenv().ok();
+            PgConnection::establish(&env::var("DATABASE_URL").unwrap())
+        }
+    }
+
+    impl NotifyConnectionProvider for TestConnectionProvider {
+        fn connection_url(&self) -> String {
+            dotenv::dotenv().ok();
+            env::var("DATABASE_URL").unwrap()
+        }
+    }
+
+    fn row_exists(id: i32) -> bool {
+        use crate::schema::please_ids;
+
+        let conn = TestConnectionProvider.get().expect("Failed to get connection");
+        please_ids::table
+            .filter(please_ids::id.eq(id))
+            .count()
+            .get_result::<i64>(&conn)
+            .expect("Failed to count rows") > 0
+    }
+
+    #[test]
+    fn wait_until_free_wakes_on_expiry() {
+        let mut handle = PleaseHandle::new(TestConnectionProvider, "wait_until_free_wakes_on_expiry")
+            .expect("Failed to create handle");
+        let id = handle.id();
+
+        let waiter = thread::spawn(move || {
+            PleaseHandle::wait_until_free(
+                &TestConnectionProvider,
+                Duration::from_millis(50),
+                move || Ok::<bool, PleaseError<diesel::ConnectionError>>(row_exists(id)),
+                move |notified_id| notified_id == id,
+            )
+        });
+
+        // Give the waiter thread time to open its LISTEN connection before
+        // we expire the handle it's waiting on.
+        thread::sleep(Duration::from_millis(200));
+
+        handle.expire().expect("Failed to expire handle");
+
+        waiter.join()
+            .expect("Waiter thread panicked")
+            .expect("wait_until_free should return once the handle expires");
+    }
+}